@@ -1,11 +1,16 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use std::fs;
 use std::process::Command;
 
 use camino::Utf8Path;
+use camino::Utf8PathBuf;
 use clap::Args;
+use sha2::Digest;
+use sha2::Sha256;
 
+use crate::python_dist::ensure_standalone_python;
 use crate::run::run_silent;
 
 #[derive(Args)]
@@ -14,30 +19,279 @@ pub struct PyenvArgs {
     pyenv_folder: String,
     initial_reqs: String,
     reqs: Vec<String>,
+    /// Path to a pinned uv binary; when provided, uv is used instead of pip/pip-sync.
+    #[arg(long)]
+    uv: Option<String>,
+    /// Python version to provision a managed, reproducible standalone interpreter for (e.g.
+    /// "3.11"). When set, this takes precedence over `python_bin`.
+    #[arg(long)]
+    python: Option<String>,
+    /// Install strictly from `wheel_dir` with no network access, refusing to proceed unless every
+    /// requirement is hash-pinned and present in the local store. For air-gapped CI and
+    /// distribution packaging (Nix, Void, ...).
+    #[arg(long)]
+    locked: bool,
+    /// Directory of pre-populated wheels to install from when `--locked` is set.
+    #[arg(long)]
+    wheel_dir: Option<String>,
 }
 
 /// Set up a venv if one doesn't already exist, and then sync packages with provided requirements file.
 pub fn setup_pyenv(args: PyenvArgs) {
     let pyenv_folder = Utf8Path::new(&args.pyenv_folder);
 
-    let pyenv_bin_folder = pyenv_folder.join(if cfg!(windows) { "scripts" } else { "bin" });
-    let pip = pyenv_bin_folder.join("pip");
-    let pip_sync = pyenv_bin_folder.join("pip-sync");
+    let python_bin = match &args.python {
+        Some(version) => ensure_standalone_python(version),
+        None => args.python_bin.clone().into(),
+    };
 
-    if !pip.exists() {
-        run_silent(Command::new(&args.python_bin).args(["-m", "venv", pyenv_folder.as_str()]));
+    if let Some(uv) = &args.uv {
+        setup_pyenv_uv(uv, &args, &python_bin, pyenv_folder);
+    } else {
+        setup_pyenv_pip(&args, &python_bin, pyenv_folder);
+    }
+}
+
+/// The resolved location of a venv's bin/Scripts directory and the executables inside it. Probed
+/// rather than assumed, since some tooling creates venvs with a capitalized `Scripts` folder and
+/// `.exe`-suffixed executables even outside of Windows cross-compilation.
+struct VenvLayout {
+    pip: Utf8PathBuf,
+    pip_sync: Utf8PathBuf,
+}
+
+impl VenvLayout {
+    /// Probes `pyenv_folder` for an existing bin/Scripts directory, falling back to the
+    /// platform's conventional name if the venv hasn't been created yet.
+    fn probe(pyenv_folder: &Utf8Path) -> Self {
+        let bin_folder = ["bin", "Scripts", "scripts"]
+            .into_iter()
+            .map(|name| pyenv_folder.join(name))
+            .find(|candidate| candidate.is_dir())
+            .unwrap_or_else(|| pyenv_folder.join(if cfg!(windows) { "Scripts" } else { "bin" }));
+
+        Self {
+            pip: resolve_executable(&bin_folder, "pip"),
+            pip_sync: resolve_executable(&bin_folder, "pip-sync"),
+        }
+    }
+}
+
+/// Resolves an executable's path within `bin_folder`, preferring a `.exe`-suffixed candidate when
+/// one exists rather than assuming the suffix based on `cfg!(windows)` alone.
+fn resolve_executable(bin_folder: &Utf8Path, name: &str) -> Utf8PathBuf {
+    let exe = bin_folder.join(format!("{name}.exe"));
+    if exe.exists() {
+        return exe;
+    }
+    bin_folder.join(name)
+}
+
+fn setup_pyenv_pip(args: &PyenvArgs, python_bin: &Utf8Path, pyenv_folder: &Utf8Path) {
+    let mut layout = VenvLayout::probe(pyenv_folder);
+
+    if !layout.pip.exists() {
+        run_silent(Command::new(python_bin).args(["-m", "venv", pyenv_folder.as_str()]));
+        clear_reqs_stamp(pyenv_folder);
+        layout = VenvLayout::probe(pyenv_folder);
+
+        // The bootstrap install must honor --locked too, or the very first venv creation phones
+        // home to PyPI regardless of the flag.
+        let initial_wheel_dir = args.locked.then(|| {
+            let wheel_dir = locked_wheel_dir(args);
+            require_locked_reqs_satisfiable(std::slice::from_ref(&args.initial_reqs), &wheel_dir);
+            wheel_dir
+        });
 
         if cfg!(windows) {
             // the first install on Windows throws an error the first time pip is upgraded, so we install
             // it twice and swallow the first error
-            let _output = Command::new(&pip)
-                .args(["install", "-r", &args.initial_reqs])
-                .output()
-                .unwrap();
+            let mut first = Command::new(&layout.pip);
+            first.args(["install", "-r", &args.initial_reqs]);
+            if let Some(wheel_dir) = &initial_wheel_dir {
+                apply_locked_pip_flags(&mut first, wheel_dir);
+            }
+            let _output = first.output().unwrap();
+        }
+
+        let mut initial_install = Command::new(&layout.pip);
+        initial_install.args(["install", "-r", &args.initial_reqs]);
+        if let Some(wheel_dir) = &initial_wheel_dir {
+            apply_locked_pip_flags(&mut initial_install, wheel_dir);
+        }
+        run_silent(&mut initial_install);
+    }
+
+    // --locked must always re-validate and re-run with its offline/hash-verifying flags, even if
+    // the reqs+interpreter fingerprint hasn't changed since the last (possibly non-locked) sync.
+    if !args.locked && reqs_up_to_date(pyenv_folder, python_bin, &args.reqs) {
+        return;
+    }
+
+    let mut cmd = Command::new(&layout.pip_sync);
+    cmd.args(&args.reqs);
+    if args.locked {
+        let wheel_dir = locked_wheel_dir(args);
+        require_locked_reqs_satisfiable(&args.reqs, &wheel_dir);
+        apply_locked_pip_flags(&mut cmd, &wheel_dir);
+    }
+    run_silent(&mut cmd);
+    write_reqs_stamp(pyenv_folder, python_bin, &args.reqs);
+}
+
+fn apply_locked_pip_flags(cmd: &mut Command, wheel_dir: &Utf8Path) {
+    cmd.args(["--no-index", "--find-links", wheel_dir.as_str()]);
+    cmd.arg("--require-hashes");
+}
+
+/// uv parallelizes downloads and uses a global wheel cache, so we prefer it to pip when available;
+/// it also doesn't need the Windows double-install workaround.
+fn setup_pyenv_uv(uv: &str, args: &PyenvArgs, python_bin: &Utf8Path, pyenv_folder: &Utf8Path) {
+    // `uv venv` doesn't seed pip/setuptools/wheel by default, so a pip executable is not a
+    // reliable "venv already created" signal here; pyvenv.cfg is what uv itself always writes.
+    if !pyenv_folder.join("pyvenv.cfg").exists() {
+        run_silent(Command::new(uv).args([
+            "venv",
+            "--python",
+            python_bin.as_str(),
+            pyenv_folder.as_str(),
+        ]));
+        clear_reqs_stamp(pyenv_folder);
+    }
+
+    // --locked must always re-validate and re-run with its offline flags, even if the
+    // reqs+interpreter fingerprint hasn't changed since the last (possibly non-locked) sync.
+    if !args.locked && reqs_up_to_date(pyenv_folder, python_bin, &args.reqs) {
+        return;
+    }
+
+    let mut cmd = Command::new(uv);
+    cmd.args(["pip", "sync"])
+        .args(&args.reqs)
+        .env("VIRTUAL_ENV", pyenv_folder.as_str());
+    if args.locked {
+        let wheel_dir = locked_wheel_dir(args);
+        require_locked_reqs_satisfiable(&args.reqs, &wheel_dir);
+        cmd.args(["--offline", "--find-links", wheel_dir.as_str()]);
+    }
+    run_silent(&mut cmd);
+    write_reqs_stamp(pyenv_folder, python_bin, &args.reqs);
+}
+
+/// Name of the stamp file used to skip redundant syncs when requirements and the interpreter
+/// haven't changed since the last sync.
+const REQS_STAMP_FILE: &str = ".reqs-stamp";
+
+fn reqs_stamp_path(pyenv_folder: &Utf8Path) -> Utf8PathBuf {
+    pyenv_folder.join(REQS_STAMP_FILE)
+}
+
+/// Hashes the contents of all `reqs` files plus the resolved interpreter path, so a change to
+/// either invalidates the stamp.
+fn reqs_fingerprint(python_bin: &Utf8Path, reqs: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(python_bin.as_str().as_bytes());
+    for req in reqs {
+        if let Ok(contents) = fs::read(req) {
+            hasher.update(&contents);
         }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn reqs_up_to_date(pyenv_folder: &Utf8Path, python_bin: &Utf8Path, reqs: &[String]) -> bool {
+    let Ok(stamped) = fs::read_to_string(reqs_stamp_path(pyenv_folder)) else {
+        return false;
+    };
+    stamped == reqs_fingerprint(python_bin, reqs)
+}
 
-        run_silent(Command::new(pip).args(["install", "-r", &args.initial_reqs]));
+fn write_reqs_stamp(pyenv_folder: &Utf8Path, python_bin: &Utf8Path, reqs: &[String]) {
+    fs::write(
+        reqs_stamp_path(pyenv_folder),
+        reqs_fingerprint(python_bin, reqs),
+    )
+    .expect("write reqs stamp");
+}
+
+fn clear_reqs_stamp(pyenv_folder: &Utf8Path) {
+    let _ = fs::remove_file(reqs_stamp_path(pyenv_folder));
+}
+
+fn locked_wheel_dir(args: &PyenvArgs) -> Utf8PathBuf {
+    Utf8PathBuf::from(
+        args.wheel_dir
+            .as_deref()
+            .expect("--locked requires --wheel-dir to be set"),
+    )
+}
+
+/// Fails loudly instead of silently falling back to the network: every requirement must be
+/// hash-pinned, and `wheel_dir` must already contain wheels to satisfy them.
+fn require_locked_reqs_satisfiable(reqs: &[String], wheel_dir: &Utf8Path) {
+    let mut required_packages = Vec::new();
+
+    for req in reqs {
+        let contents =
+            fs::read_to_string(req).unwrap_or_else(|e| panic!("failed to read {req}: {e}"));
+        // Join backslash-continued lines so a requirement and its `--hash=` pins are checked as one.
+        let contents = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !line.contains("--hash=") {
+                panic!(
+                    "--locked requires every requirement to be hash-pinned, but {req} has an unpinned line: {line}"
+                );
+            }
+            if let Some(name) = req_package_name(line) {
+                required_packages.push(name);
+            }
+        }
+    }
+
+    let wheel_names: Vec<String> = fs::read_dir(wheel_dir)
+        .unwrap_or_else(|e| panic!("--locked wheel dir {wheel_dir} does not exist: {e}"))
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".whl"))
+        .collect();
+
+    for package in &required_packages {
+        let normalized = normalize_pkg_name(package);
+        let satisfied = wheel_names
+            .iter()
+            .any(|wheel| normalize_pkg_name(wheel_distribution_name(wheel)) == normalized);
+        if !satisfied {
+            panic!("--locked wheel dir {wheel_dir} has no wheel for required package {package}");
+        }
     }
+}
+
+/// Extracts the distribution name segment from a wheel filename, i.e. everything before the
+/// first `-` (the wheel spec requires the distribution name itself contain no `-`, so this always
+/// isolates it from the version/tag segments that follow).
+fn wheel_distribution_name(wheel_filename: &str) -> &str {
+    wheel_filename.split('-').next().unwrap_or(wheel_filename)
+}
+
+/// Extracts the package name from a requirements line, stripping version specifiers, markers and
+/// `--hash=` pins (e.g. `foo-bar==1.0 ; python_version >= "3.9" --hash=sha256:...` -> `foo-bar`).
+fn req_package_name(line: &str) -> Option<String> {
+    let name = line
+        .split("--hash")
+        .next()
+        .unwrap_or(line)
+        .split(|c: char| "=<>!~; \t[".contains(c))
+        .next()?
+        .trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
 
-    run_silent(Command::new(pip_sync).args(&args.reqs));
+/// Normalizes a package or wheel-file name per PEP 503/427 so names that differ only in case or
+/// `-`/`_`/`.` separators compare equal.
+fn normalize_pkg_name(name: &str) -> String {
+    name.to_lowercase().replace(['-', '.'], "_")
 }
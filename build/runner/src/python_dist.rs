@@ -0,0 +1,134 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Provisions a pinned, standalone CPython interpreter so that venv creation does not depend on
+//! whatever Python happens to be on `PATH`. Builds are downloaded from
+//! https://github.com/indygreg/python-build-standalone, verified against a pinned SHA256, and
+//! cached on disk keyed by version and platform so repeat builds are a no-op.
+
+use std::fs;
+use std::io::Cursor;
+use std::io::Read;
+
+use camino::Utf8PathBuf;
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+/// A (version, os, arch) triple identifying a standalone CPython build.
+struct PythonTarget {
+    version: String,
+    os: &'static str,
+    arch: &'static str,
+}
+
+impl PythonTarget {
+    fn current(version: &str) -> Self {
+        let os = if cfg!(windows) {
+            "windows"
+        } else if cfg!(target_os = "macos") {
+            "macos"
+        } else {
+            "linux"
+        };
+        let arch = if cfg!(target_arch = "aarch64") {
+            "aarch64"
+        } else {
+            "x86_64"
+        };
+        Self {
+            version: version.to_string(),
+            os,
+            arch,
+        }
+    }
+
+    fn platform_tag(&self) -> String {
+        format!("{}-{}", self.arch, self.os)
+    }
+
+    /// Resolves the download URL and expected SHA256 for a known (version, os, arch) triple.
+    /// New entries are added here as we adopt newer Python versions.
+    fn resolve(&self) -> (&'static str, &'static str) {
+        match (self.version.as_str(), self.os, self.arch) {
+            ("3.11", "linux", "x86_64") => (
+                "https://github.com/indygreg/python-build-standalone/releases/download/20231002/cpython-3.11.6+20231002-x86_64-unknown-linux-gnu-install_only.tar.gz",
+                "2eb0ecb622e5d6020f5aa0bb2226d92780868d2910bc0a0e3b1c6d90a147b6a7",
+            ),
+            ("3.11", "macos", "aarch64") => (
+                "https://github.com/indygreg/python-build-standalone/releases/download/20231002/cpython-3.11.6+20231002-aarch64-apple-darwin-install_only.tar.gz",
+                "321af2c44503cd4953ecad25e7cac7034cd4e3ea86df0735bbc4293c7b45cbfb",
+            ),
+            ("3.11", "windows", "x86_64") => (
+                "https://github.com/indygreg/python-build-standalone/releases/download/20231002/cpython-3.11.6+20231002-x86_64-pc-windows-msvc-shared-install_only.tar.gz",
+                "8d482744ac7099516786a50bf070e7e8039ea835f586ec3234174f8931bbe4b0",
+            ),
+            _ => panic!(
+                "no standalone Python build registered for {} ({})",
+                self.version,
+                self.platform_tag()
+            ),
+        }
+    }
+}
+
+fn cache_root() -> Utf8PathBuf {
+    match std::env::var("ANKI_PYTHON_CACHE") {
+        Ok(dir) => Utf8PathBuf::from(dir),
+        Err(_) => Utf8PathBuf::from(".python-cache"),
+    }
+}
+
+/// Ensures a standalone interpreter for `version` is downloaded and unpacked, returning the path
+/// to the `bin/python3` (or, on Windows, `python.exe`) binary.
+pub fn ensure_standalone_python(version: &str) -> Utf8PathBuf {
+    let target = PythonTarget::current(version);
+    let install_dir = cache_root().join(format!("cpython-{}-{}", version, target.platform_tag()));
+    let sentinel = install_dir.join(".ok");
+
+    if !sentinel.exists() {
+        let (url, expected_sha256) = target.resolve();
+        let archive = download(url);
+        verify_sha256(&archive, expected_sha256);
+        unpack(&archive, &install_dir);
+        fs::write(&sentinel, "").expect("write sentinel");
+    }
+
+    install_dir.join("python").join(if cfg!(windows) {
+        "python.exe"
+    } else {
+        "bin/python3"
+    })
+}
+
+fn download(url: &str) -> Vec<u8> {
+    let response = ureq::get(url)
+        .call()
+        .unwrap_or_else(|e| panic!("failed to download {url}: {e}"));
+    let mut buf = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut buf)
+        .expect("read response body");
+    buf
+}
+
+fn verify_sha256(data: &[u8], expected: &str) {
+    use sha2::{Digest, Sha256};
+
+    let digest = Sha256::digest(data);
+    let actual = format!("{digest:x}");
+    assert_eq!(
+        actual, expected,
+        "checksum mismatch for downloaded standalone Python build"
+    );
+}
+
+fn unpack(archive: &[u8], dest: &Utf8PathBuf) {
+    if dest.exists() {
+        fs::remove_dir_all(dest).expect("clear stale install dir");
+    }
+    fs::create_dir_all(dest).expect("create install dir");
+    Archive::new(GzDecoder::new(Cursor::new(archive)))
+        .unpack(dest)
+        .expect("unpack standalone Python archive");
+}